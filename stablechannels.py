@@ -14,6 +14,7 @@ from pyln.client import LightningRpc
 from collections import namedtuple # Standard on Python 3
 from cachetools import cached, TTLCache # Used to handle price feed calls; probably can remove
 import requests # Standard on Python 3.7+
+import json # Standard on Python 3
 from requests.adapters import HTTPAdapter 
 from requests.packages.urllib3.util.retry import Retry
 import statistics # Standard on Python 3
@@ -21,6 +22,8 @@ import time # Standard on Python 3
 from datetime import datetime 
 from apscheduler.schedulers.blocking import BlockingScheduler # Used to check balances every 5 minutes
 import threading # Standard on Python 3
+import os # Standard on Python 3
+import tempfile # Standard on Python 3
 
 plugin = Plugin()
 
@@ -41,7 +44,8 @@ class StableChannel:
         stable_provider_dollar_amount: float,
         timestamp: int,
         formatted_datetime: str,
-        payment_made: bool
+        payment_made: bool,
+        smoothed_expected_msats: float
     ):
         self.plugin = plugin
         self.short_channel_id = short_channel_id
@@ -58,6 +62,7 @@ class StableChannel:
         self.timestamp = timestamp
         self.formatted_datetime = datetime
         self.payment_made = payment_made
+        self.smoothed_expected_msats = smoothed_expected_msats
 
 # Section 2 - Price feed config and logic
 Source = namedtuple('Source', ['name', 'urlformat', 'replymembers'])
@@ -86,6 +91,26 @@ sources = [
            ['{currency}', 'last']),
 ]
 
+# Price feed errors, so callers can tell a feed that couldn't be reached from one
+# that answered with something unusable, and both from no feed working at all
+class PriceFeedError(Exception):
+    pass
+
+# The feed couldn't be fetched: connection error, bad status, timeout or too large
+class PriceFeedNetworkError(PriceFeedError):
+    pass
+
+# The feed answered but its response has no usable rate
+class PriceFeedParseError(PriceFeedError):
+    pass
+
+# No feed gave a rate; errors maps each feed name to its PriceFeedError
+class AllFeedsFailedError(PriceFeedError):
+    def __init__(self, currency, errors):
+        super().__init__("No values available for currency {}".format(currency))
+        self.currency = currency
+        self.errors = errors
+
 # Request logic is from "currencyrate" plugin: 
 # https://github.com/lightningd/plugins/blob/master/currencyrate
 def requests_retry_session(
@@ -93,12 +118,14 @@ def requests_retry_session(
     backoff_factor=0.3,
     status_forcelist=(500, 502, 504),
     session=None,
+    read_retries=None,
+    connect_retries=None,
 ):
     session = session or requests.Session()
     retry = Retry(
         total=retries,
-        read=retries,
-        connect=retries,
+        read=retries if read_retries is None else read_retries,
+        connect=retries if connect_retries is None else connect_retries,
         backoff_factor=backoff_factor,
         status_forcelist=status_forcelist,
     )
@@ -112,30 +139,88 @@ def get_currencyrate(plugin, currency, urlformat, replymembers):
     # Workaround: retry up to 5 times with a delay
     currency_lc = currency.lower()
     url = urlformat.format(currency_lc=currency_lc, currency=currency)
-    r = requests_retry_session(retries=5, status_forcelist=[404]).get(url, proxies=plugin.proxies)
+    # The whole fetch, connecting and reading the body, gets stable-price-timeout
+    # seconds. A feed past that deadline is skipped; a single stalled read can
+    # overshoot it by at most one more timeout. Read timeouts are not retried and
+    # connect errors are retried once. The body is capped at stable-price-max-bytes.
+    deadline = time.monotonic() + plugin.price_timeout
+    try:
+        r = requests_retry_session(retries=5, status_forcelist=[404], read_retries=0, connect_retries=1).get(
+            url, proxies=plugin.proxies, timeout=plugin.price_timeout, stream=True)
+    except requests.exceptions.RequestException as e:
+        raise PriceFeedNetworkError('{}: request failed {}'.format(url, e))
+
+    with r:
+        if r.status_code != 200:
+            raise PriceFeedNetworkError('{}: bad response {}'.format(url, r.status_code))
+
+        body = bytearray()
+        try:
+            while len(body) <= plugin.price_max_bytes:
+                if time.monotonic() > deadline:
+                    raise PriceFeedNetworkError('{}: no full response within {}s'.format(url, plugin.price_timeout))
+                chunk = r.raw.read1(8192, decode_content=True)
+                if not chunk:
+                    break
+                body += chunk
+        except PriceFeedError:
+            raise
+        except Exception as e:
+            raise PriceFeedNetworkError('{}: could not read response {}'.format(url, e))
+
+    if len(body) > plugin.price_max_bytes:
+        raise PriceFeedNetworkError('{}: response larger than {} bytes'.format(url, plugin.price_max_bytes))
+
+    rate = parse_currencyrate(body, currency, replymembers)
+    if rate is None:
+        raise PriceFeedParseError('{}: no rate in response {}'.format(url, bytes(body[:200])))
+    return rate
+
+# Walks replymembers down a feed's JSON body to the price and converts it to
+# msat per unit of currency. Returns None if the body doesn't have that shape.
+def parse_currencyrate(body, currency, replymembers):
+    currency_lc = currency.lower()
 
-    if r.status_code != 200:
-        plugin.log(level='info', message='{}: bad response {}'.format(url, r.status_code))
+    try:
+        reply = json.loads(body)
+    except ValueError:
         return None
 
-    json = r.json()
     for m in replymembers:
         expanded = m.format(currency_lc=currency_lc, currency=currency)
-        if expanded not in json:
-            plugin.log(level='debug', message='{}: {} not in {}'.format(url, expanded, json))
+        if not isinstance(reply, dict) or expanded not in reply:
             return None
-        json = json[expanded]
+        reply = reply[expanded]
 
     try:
-        return Millisatoshi(int(10**11 / float(json)))
-    except Exception:
-        plugin.log(level='info', message='{}: could not convert {} to msat'.format(url, json))
+        msat = int(10**11 / float(reply))
+    except (TypeError, ValueError, ZeroDivisionError, OverflowError):
         return None
 
+    # A negative or absurdly large price is not a usable rate
+    if msat <= 0:
+        return None
+    return Millisatoshi(msat)
+
+# CLN 23.08+ nests values under "configs" as {"value_bool": ...} / {"value_str": ...};
+# older versions put them at the top level
+def get_config_value(config, name):
+    if 'configs' in config:
+        entry = config['configs'].get(name)
+        if entry is None:
+            return None
+        for key in ('value_bool', 'value_str', 'value_int', 'value_msat'):
+            if key in entry:
+                return entry[key]
+        return None
+    return config.get(name)
+
 def set_proxies(plugin):
     config = plugin.rpc.listconfigs()
-    if 'always-use-proxy' in config and config['always-use-proxy']:
-        paddr = config['proxy']
+    if get_config_value(config, 'always-use-proxy'):
+        paddr = get_config_value(config, 'proxy')
+        if not paddr:
+            raise Exception("always-use-proxy is set but no proxy is configured")
         # Default port in 9050
         if ':' not in paddr:
             paddr += ':9050'
@@ -149,24 +234,99 @@ def set_proxies(plugin):
 @cached(cache=TTLCache(maxsize=1024, ttl=60))
 def get_rates(plugin, currency):
     rates = {}
+    errors = {}
+    feeds = {}
     for s in sources:
-        r = get_currencyrate(plugin, currency, s.urlformat, s.replymembers)
+        start = time.monotonic()
+        try:
+            r = get_currencyrate(plugin, currency, s.urlformat, s.replymembers)
+        except PriceFeedError as e:
+            plugin.log(level='info', message=str(e))
+            errors[s.name] = e
+            r = None
+        feeds[s.name] = {"seconds": round(time.monotonic() - start, 3),
+                         "ok": r is not None,
+                         "msat": int(r) if r is not None else None}
         if r is not None:
             rates[s.name] = r
 
-    print("rates line 165",rates)
-    return rates
+    # Keep the last fetch per feed so slow or failing feeds can be spotted with pricefeedlatency
+    plugin.feed_stats = feeds
+
+    plugin.log(level='debug', message='{}: rates {}'.format(currency, rates))
+    plugin.log(level='debug', message='{}: feeds {}, slowest {}'.format(currency, feeds, slowest_feed(feeds)))
+    return rates, errors
+
+def slowest_feed(feeds):
+    if len(feeds) == 0:
+        return None
+    return max(feeds, key=lambda name: feeds[name]["seconds"])
+
+@plugin.method("pricefeedlatency")
+def pricefeedlatency(plugin):
+    """Shows each price feed's time in seconds on the last fetch, whether it
+    returned a rate, and that rate in msat per unit of currency.
+
+    The time covers the whole fetch, including retries and backoff, not
+    a single round trip.
+    """
+    feeds = getattr(plugin, 'feed_stats', {})
+    slowest = slowest_feed(feeds)
+    if slowest is None:
+        return {"feeds": {}, "slowest": None}
+
+    return {"feeds": feeds, "slowest": {"name": slowest, "seconds": feeds[slowest]["seconds"]}}
+
+# Combine the per-feed rates (msat per unit of currency) into one rate
+# median = middle feed (default)
+# trimmed-mean = mean after dropping the highest and lowest feed
+# min = lowest BTC price, i.e. the most msat per unit; the conservative choice
+aggregation_strategies = ['median', 'trimmed-mean', 'min']
+
+def aggregate_rates(rates, strategy):
+    msats = sorted(m.millisatoshis for m in rates.values())
+
+    if strategy == 'median':
+        return statistics.median(msats)
+    elif strategy == 'trimmed-mean':
+        if len(msats) > 2:
+            msats = msats[1:-1]
+        return statistics.mean(msats)
+    elif strategy == 'min':
+        return msats[-1]
+
+    raise Exception("Unknown price aggregation strategy {}".format(strategy))
+
+# Price smoothing for stabilization
+# raw = use each check's price as-is (default)
+# ewma = exponentially weighted moving average; alpha is the weight of the newest price
+smoothing_modes = ['raw', 'ewma']
+
+def ewma(previous, latest, alpha):
+    if previous is None:
+        return latest
+    return alpha * latest + (1 - alpha) * previous
+
+# Dollar value of the stable side's balance, given the msat worth of expected_dollar_amount
+def stable_dollar_amount(balance, expected_dollar_amount, expected_msats):
+    return round((int(balance) * expected_dollar_amount) / int(expected_msats), 3)
+
+# Scenario 1 threshold: differences under $0.01 are too small to correct
+def needs_correction(expected_dollar_amount, stable_receiver_dollar_amount):
+    return abs(expected_dollar_amount - float(stable_receiver_dollar_amount)) >= 0.01
 
 @plugin.method("currencyconvert")
 def currencyconvert(plugin, amount, currency):
     """Converts currency using given APIs."""
-    rates = get_rates(plugin, currency.upper())
+    rates, errors = get_rates(plugin, currency.upper())
     if len(rates) == 0:
-        raise Exception("No values available for currency {}".format(currency.upper()))
+        raise AllFeedsFailedError(currency.upper(), errors)
+
+    rate = aggregate_rates(rates, plugin.price_aggregation)
 
-    val = statistics.median([m.millisatoshis for m in rates.values()]) * float(amount)
+    val = rate * float(amount)
     
-    estimated_price = "{:.2f}".format(100000000000 / statistics.median([m.millisatoshis for m in rates.values()]))
+    estimated_price = "{:.2f}".format(100000000000 / rate)
 
     return ({"msat": Millisatoshi(round(val))}, estimated_price)
 
@@ -179,6 +339,16 @@ def start_scheduler(sc):
     scheduler.add_job(check_stables, 'cron', minute='0/5', args=[sc])
     scheduler.start()
 
+# The only place funds leave the node. In observer mode nothing is sent,
+# but the check still runs and is logged.
+def send_stability_payment(l1, sc, amount):
+    if plugin.observer:
+        plugin.log(level='info', message='observer mode: not sending {} msat to {}'.format(amount, sc.counterparty))
+        return False
+
+    l1.keysend(sc.counterparty, amount)
+    return True
+
 # 5 scenarios to handle
 # Scenario 1 - Difference to small to worry about (under $0.01) = do nothing
 # Scenario 2 - Node is stableReceiver and expects to get paid = wait 30 seconds; check on payment
@@ -189,10 +359,22 @@ def start_scheduler(sc):
 def check_stables(sc):
     l1 = LightningRpc(sc.lightning_rpc_path)
 
-    msat_dict, estimated_price = currencyconvert(plugin, sc.expected_dollar_amount, "USD")
+    # Without a price there is nothing to compare against; try again next run
+    try:
+        msat_dict, estimated_price = currencyconvert(plugin, sc.expected_dollar_amount, "USD")
+    except AllFeedsFailedError as e:
+        kinds = sorted(set(type(err).__name__ for err in e.errors.values()))
+        plugin.log(level='warn', message='Skipping stability check: {} ({})'.format(e, ', '.join(kinds)))
+        return
 
     expected_msats = msat_dict["msat"]
 
+    # Smooth the target so a one-tick spike doesn't trigger a full correction
+    if plugin.price_smoothing == 'ewma':
+        sc.smoothed_expected_msats = ewma(sc.smoothed_expected_msats, int(expected_msats), plugin.price_ewma_alpha)
+        expected_msats = Millisatoshi(round(sc.smoothed_expected_msats))
+        estimated_price = "{:.2f}".format(sc.expected_dollar_amount * 100000000000 / int(expected_msats))
+
     # Get channel data  
     list_funds_data = l1.listfunds()
     channels = list_funds_data.get("channels", [])
@@ -205,9 +387,9 @@ def check_stables(sc):
 
     # Get Stable Receiver dollar amount
     if sc.is_stable_receiver:
-        sc.stable_receiver_dollar_amount = round((int(sc.our_balance) * sc.expected_dollar_amount) / int(expected_msats), 3)
+        sc.stable_receiver_dollar_amount = stable_dollar_amount(sc.our_balance, sc.expected_dollar_amount, expected_msats)
     else:
-        sc.stable_receiver_dollar_amount = round((int(sc.their_balance) * sc.expected_dollar_amount) / int(expected_msats), 3)
+        sc.stable_receiver_dollar_amount = stable_dollar_amount(sc.their_balance, sc.expected_dollar_amount, expected_msats)
 
     formatted_time = datetime.utcnow().strftime("%H:%M %d %b %Y")
     
@@ -215,7 +397,7 @@ def check_stables(sc):
     amount_too_small = False
 
     # Scenario 1 - Difference to small to worry about (under $0.01) = do nothing
-    if abs(sc.expected_dollar_amount - float(sc.stable_receiver_dollar_amount)) < 0.01:
+    if not needs_correction(sc.expected_dollar_amount, sc.stable_receiver_dollar_amount):
         amount_too_small = True
     else:
         # Round difference to nearest msat; we may need to pay it
@@ -239,7 +421,7 @@ def check_stables(sc):
                 if channel.get("short_channel_id") == sc.short_channel_id:
                     new_our_balance = channel.get("our_amount_msat")
                   
-            new_stable_receiver_dollar_amount = round((int(new_our_balance) * sc.expected_dollar_amount) / int(expected_msats), 3)
+            new_stable_receiver_dollar_amount = stable_dollar_amount(new_our_balance, sc.expected_dollar_amount, expected_msats)
 
             if sc.expected_dollar_amount - float(new_stable_receiver_dollar_amount) < 0.01:
                 sc.payment_made = True
@@ -250,10 +432,8 @@ def check_stables(sc):
 
         elif not(sc.is_stable_receiver):
             # Scenario 3 - Node is stableProvider and needs to pay = keysend and exit
-            result = l1.keysend(sc.counterparty,may_need_to_pay_amount)
-            
             # TODO - error handling
-            sc.payment_made = True
+            sc.payment_made = send_stability_payment(l1, sc, may_need_to_pay_amount)
 
     elif amount_too_small:
         sc.payment_made = False
@@ -263,10 +443,8 @@ def check_stables(sc):
     elif not amount_too_small and sc.stable_receiver_dollar_amount > sc.expected_dollar_amount:
         # 4 - Node is stableReceiver and needs to pay = keysend
         if sc.is_stable_receiver:
-            result = l1.keysend(sc.counterparty,may_need_to_pay_amount)
-            
             # TODO - error handling
-            sc.payment_made = True
+            sc.payment_made = send_stability_payment(l1, sc, may_need_to_pay_amount)
 
         # Scenario 5 - Node is stableProvider and expects to get paid = wait 30 seconds; check on payment
         elif not(sc.is_stable_receiver):
@@ -275,7 +453,6 @@ def check_stables(sc):
             list_funds_data = l1.listfunds()
 
             channels = list_funds_data.get("channels", [])
-            print(channels)
     
             for channel in channels:
                 if channel.get("short_channel_id") == sc.short_channel_id:
@@ -284,7 +461,7 @@ def check_stables(sc):
                     new_our_balance = channel.get("our_amount_msat")
                     new_their_balance = Millisatoshi.__sub__(channel.get("amount_msat"), new_our_balance)
 
-                    new_stable_receiver_dollar_amount = round((int(new_their_balance) * sc.expected_dollar_amount) / int(expected_msats), 3)
+                    new_stable_receiver_dollar_amount = stable_dollar_amount(new_their_balance, sc.expected_dollar_amount, expected_msats)
 
             if sc.expected_dollar_amount - float(new_stable_receiver_dollar_amount) < 0.01:
                 sc.payment_made = True
@@ -298,25 +475,71 @@ def check_stables(sc):
     # Log the result
     # How to log better?
     if sc.is_stable_receiver:
-        file_path = '/home/ubuntu/stablelog1.json'
+        file_path = os.path.join(plugin.log_dir, 'stablelog1.json')
 
         with open(file_path, 'a') as file:
             file.write(json_line)
 
     elif not(sc.is_stable_receiver):
-        file_path = '/home/ubuntu/stablelog2.json'
+        file_path = os.path.join(plugin.log_dir, 'stablelog2.json')
 
         with open(file_path, 'a') as file:
             file.write(json_line)
 
 # Section 4 - Plug-in initialization
+def dir_is_writable(path):
+    try:
+        tempfile.TemporaryFile(dir=path).close()
+        return True
+    except OSError:
+        return False
+
 @plugin.init()
 def init(options, configuration, plugin):
-    print("here")
     set_proxies(plugin)
+    plugin.price_timeout = float(options['stable-price-timeout'])
+    if plugin.price_timeout <= 0:
+        raise Exception("stable-price-timeout must be greater than 0")
+
+    plugin.price_max_bytes = int(options['stable-price-max-bytes'])
+    if plugin.price_max_bytes <= 0:
+        raise Exception("stable-price-max-bytes must be greater than 0")
+
+    # A directory the operator asked for must be usable; the default is only warned about
+    plugin.log_dir = options['stable-log-dir'] or '/home/ubuntu'
+    if options['stable-log-dir']:
+        try:
+            os.makedirs(plugin.log_dir, exist_ok=True)
+        except OSError as e:
+            raise Exception("stable-log-dir: could not create {}: {}".format(plugin.log_dir, e))
+        if not dir_is_writable(plugin.log_dir):
+            raise Exception("stable-log-dir: {} is not writable".format(plugin.log_dir))
+    elif not dir_is_writable(plugin.log_dir):
+        plugin.log(level='warn', message='stable logs will not be written: {} is not writable; set stable-log-dir'.format(plugin.log_dir))
+
+    plugin.price_aggregation = options['stable-price-aggregation']
+    if plugin.price_aggregation not in aggregation_strategies:
+        raise Exception("stable-price-aggregation must be one of {}".format(', '.join(aggregation_strategies)))
+
+    plugin.price_smoothing = options['stable-price-smoothing']
+    if plugin.price_smoothing not in smoothing_modes:
+        raise Exception("stable-price-smoothing must be one of {}".format(', '.join(smoothing_modes)))
+
+    plugin.price_ewma_alpha = float(options['stable-price-ewma-alpha'])
+    if not 0 < plugin.price_ewma_alpha <= 1:
+        raise Exception("stable-price-ewma-alpha must be greater than 0 and at most 1")
+
+    if options['stable-observer'] == "True":
+        plugin.observer = True
+        plugin.log(level='info', message='Observer mode: stability payments will never be sent')
+    elif options['stable-observer'] == "False":
+        plugin.observer = False
+    else:
+        raise Exception("stable-observer must be True or False")
+
     stable_details = options['stable-details']
 
-    print(str(stable_details))
+    plugin.log(level='info', message='stable-details: {}'.format(stable_details))
 
     # TODO - Pass in as plugin start args
     if stable_details != ['']:
@@ -346,17 +569,27 @@ def init(options, configuration, plugin):
                 stable_provider_dollar_amount=0,        
                 timestamp=0,
                 formatted_datetime='',
-                payment_made=False
+                payment_made=False,
+                smoothed_expected_msats=None
             )
 
     # need to start a new thread so init funciotn can return
     threading.Thread(target=start_scheduler, args=(sc,)).start()
     
 plugin.add_option(name='stable-details', default='', description='Input stable details.')
+plugin.add_option(name='stable-price-timeout', default='10', description='Seconds each price feed gets to connect and send its whole response.')
+plugin.add_option(name='stable-price-max-bytes', default='65536', description='Largest price feed response to accept, in bytes.')
+plugin.add_option(name='stable-log-dir', default='', description='Directory for the stablelog1.json / stablelog2.json files (default /home/ubuntu).')
+plugin.add_option(name='stable-price-aggregation', default='median', description='How to combine price feeds: median, trimmed-mean or min.')
+plugin.add_option(name='stable-observer', default='False', description='True to track and log stable channels without ever sending payments.')
+plugin.add_option(name='stable-price-smoothing', default='raw', description='Price used for stabilization: raw or ewma.')
+plugin.add_option(name='stable-price-ewma-alpha', default='0.3', description='Weight of the newest price in ewma mode, between 0 and 1.')
 
 # This has an effect only for recent pyln versions (0.9.3+).
 plugin.options['stable-details']['multi'] = True
 
-plugin.run()
+# Guarded so the helpers can be imported by the tests without starting the plug-in
+if __name__ == '__main__':
+    plugin.run()
 
 
@@ -0,0 +1,44 @@
+# Run from the repo root: python3 -m unittest discover tests
+import unittest
+
+from pyln.client import Millisatoshi
+
+from stablechannels import aggregate_rates, aggregation_strategies
+
+# msat per $1, i.e. BTC at roughly $52,632, $50,000, $48,544, $47,619 and $33,333
+FIVE_FEEDS = {
+    'bitstamp': Millisatoshi(1900000),
+    'coingecko': Millisatoshi(2000000),
+    'coindesk': Millisatoshi(2060000),
+    'coinbase': Millisatoshi(2100000),
+    'blockchain.info': Millisatoshi(3000000),
+}
+
+class TestAggregateRates(unittest.TestCase):
+    def test_median(self):
+        self.assertEqual(aggregate_rates(FIVE_FEEDS, 'median'), 2060000)
+
+    def test_trimmed_mean_drops_highest_and_lowest(self):
+        self.assertAlmostEqual(aggregate_rates(FIVE_FEEDS, 'trimmed-mean'), (2000000 + 2060000 + 2100000) / 3)
+
+    def test_min_is_lowest_price(self):
+        # Lowest BTC price is the most msat per dollar
+        self.assertEqual(aggregate_rates(FIVE_FEEDS, 'min'), 3000000)
+
+    def test_one_feed(self):
+        rates = {'coinbase': Millisatoshi(2000000)}
+        for strategy in aggregation_strategies:
+            self.assertEqual(aggregate_rates(rates, strategy), 2000000, strategy)
+
+    def test_two_feeds_are_not_trimmed(self):
+        rates = {'coinbase': Millisatoshi(2000000), 'bitstamp': Millisatoshi(2100000)}
+        self.assertEqual(aggregate_rates(rates, 'median'), 2050000)
+        self.assertEqual(aggregate_rates(rates, 'trimmed-mean'), 2050000)
+        self.assertEqual(aggregate_rates(rates, 'min'), 2100000)
+
+    def test_unknown_strategy(self):
+        with self.assertRaises(Exception):
+            aggregate_rates(FIVE_FEEDS, 'mean')
+
+if __name__ == '__main__':
+    unittest.main()
@@ -0,0 +1,61 @@
+# Run from the repo root: python3 -m unittest discover tests
+import os
+import tempfile
+import unittest
+from unittest import mock
+
+from pyln.client import Millisatoshi
+
+import stablechannels
+from stablechannels import StableChannel, check_stables
+
+# $100 in a 400,000,000 msat channel where we hold 200,000,000 msat
+class FakeRpc:
+    def __init__(self, path):
+        self.keysends = []
+
+    def listfunds(self):
+        return {"channels": [{"short_channel_id": "1x1x1",
+                              "our_amount_msat": Millisatoshi(200000000),
+                              "amount_msat": Millisatoshi(400000000)}]}
+
+    def keysend(self, destination, amount):
+        self.keysends.append((destination, amount))
+
+class TestObserverMode(unittest.TestCase):
+    def setUp(self):
+        self.log_dir = tempfile.TemporaryDirectory()
+        self.addCleanup(self.log_dir.cleanup)
+        for name, value in [('log', mock.Mock()), ('log_dir', self.log_dir.name), ('price_smoothing', 'raw')]:
+            patcher = mock.patch.object(stablechannels.plugin, name, value, create=True)
+            patcher.start()
+            self.addCleanup(patcher.stop)
+
+    def run_check(self, observer):
+        rpc = FakeRpc(None)
+        # Price fell from $50,000 to $40,000, so the provider owes the receiver
+        price = ({"msat": Millisatoshi(250000000)}, "40000.00")
+        sc = StableChannel(stablechannels.plugin, "1x1x1", 100, 0.2, False, "02peer", "rpc", 0, 0, 0, 0, 0, 0, '', False, None)
+        with mock.patch.object(stablechannels.plugin, 'observer', observer, create=True), \
+             mock.patch.object(stablechannels, 'LightningRpc', lambda path: rpc), \
+             mock.patch.object(stablechannels, 'currencyconvert', lambda plugin, amount, currency: price):
+            check_stables(sc)
+        return rpc, sc
+
+    def test_provider_pays_normally(self):
+        rpc, sc = self.run_check(observer=False)
+        self.assertEqual(rpc.keysends, [("02peer", 50000000)])
+        self.assertTrue(sc.payment_made)
+
+    def test_observer_never_pays_but_still_logs(self):
+        rpc, sc = self.run_check(observer=True)
+        self.assertEqual(rpc.keysends, [])
+        self.assertFalse(sc.payment_made)
+
+        with open(os.path.join(self.log_dir.name, 'stablelog2.json')) as f:
+            line = f.read()
+        self.assertIn('"stable_receiver_dollar_amount": 80.0', line)
+        self.assertIn('"payment_made": False', line)
+
+if __name__ == '__main__':
+    unittest.main()
@@ -0,0 +1,46 @@
+# Run from the repo root: python3 -m unittest discover tests
+import os
+import unittest
+
+from pyln.client import Millisatoshi
+
+from stablechannels import parse_currencyrate, sources
+
+FIXTURES = os.path.join(os.path.dirname(__file__), 'fixtures')
+
+# Price in each recorded response in fixtures/<source name>.json
+EXPECTED_USD_PRICES = {
+    'bitstamp': 17970.41,
+    'coingecko': 17885.84,
+    'coindesk': 19395.14,
+    'coinbase': 19414.63,
+    'blockchain.info': 6650.3,
+}
+
+def read_fixture(name):
+    with open(os.path.join(FIXTURES, name + '.json'), 'rb') as f:
+        return f.read()
+
+class TestParseCurrencyrate(unittest.TestCase):
+    def test_every_source_has_a_fixture(self):
+        self.assertEqual(sorted(s.name for s in sources), sorted(EXPECTED_USD_PRICES))
+
+    def test_recorded_responses(self):
+        for s in sources:
+            rate = parse_currencyrate(read_fixture(s.name), 'USD', s.replymembers)
+            self.assertEqual(rate, Millisatoshi(int(10**11 / EXPECTED_USD_PRICES[s.name])), s.name)
+
+    def test_malformed_responses_are_skipped(self):
+        coinbase = next(s for s in sources if s.name == 'coinbase')
+        for body in [b'', b'not json', b'<html>502 Bad Gateway</html>', b'[1, 2]', b'"19414.63"',
+                     b'{"data": []}', b'{"data": {"base": "BTC"}}', b'{"data": {"amount": "n/a"}}',
+                     b'{"data": {"amount": null}}', b'{"data": {"amount": "0"}}', b'{"data": {"amount": "-1"}}']:
+            self.assertIsNone(parse_currencyrate(body, 'USD', coinbase.replymembers), body)
+
+    def test_other_currency(self):
+        coindesk = next(s for s in sources if s.name == 'coindesk')
+        rate = parse_currencyrate(read_fixture('coindesk'), 'AUD', coindesk.replymembers)
+        self.assertEqual(rate, Millisatoshi(int(10**11 / 25663.5329)))
+
+if __name__ == '__main__':
+    unittest.main()
@@ -0,0 +1,52 @@
+# Run from the repo root: python3 -m unittest discover tests
+import unittest
+from unittest import mock
+
+from pyln.client import Millisatoshi
+
+import stablechannels
+from stablechannels import AllFeedsFailedError, PriceFeedNetworkError, PriceFeedParseError, currencyconvert, sources
+
+class TestPriceFeedErrors(unittest.TestCase):
+    def setUp(self):
+        patcher = mock.patch.object(stablechannels.plugin, 'log', create=True)
+        patcher.start()
+        self.addCleanup(patcher.stop)
+        stablechannels.plugin.price_aggregation = 'median'
+
+    # get_rates is cached per currency, so each test asks for its own
+    def convert(self, fake_currencyrate, currency):
+        with mock.patch.object(stablechannels, 'get_currencyrate', fake_currencyrate):
+            return currencyconvert(stablechannels.plugin, 1, currency)
+
+    def test_all_feeds_down(self):
+        def down(plugin, currency, urlformat, replymembers):
+            raise PriceFeedNetworkError('down')
+
+        with self.assertRaises(AllFeedsFailedError) as cm:
+            self.convert(down, 'XAA')
+        self.assertEqual(sorted(cm.exception.errors), sorted(s.name for s in sources))
+        for err in cm.exception.errors.values():
+            self.assertIsInstance(err, PriceFeedNetworkError)
+
+    def test_all_feeds_unparseable(self):
+        def garbage(plugin, currency, urlformat, replymembers):
+            raise PriceFeedParseError('garbage')
+
+        with self.assertRaises(AllFeedsFailedError) as cm:
+            self.convert(garbage, 'XAB')
+        for err in cm.exception.errors.values():
+            self.assertIsInstance(err, PriceFeedParseError)
+
+    def test_one_working_feed_is_enough(self):
+        def only_coinbase(plugin, currency, urlformat, replymembers):
+            if 'coinbase' in urlformat:
+                return Millisatoshi(2000000)
+            raise PriceFeedParseError('garbage')
+
+        msat_dict, estimated_price = self.convert(only_coinbase, 'XAC')
+        self.assertEqual(msat_dict['msat'], Millisatoshi(2000000))
+        self.assertEqual(estimated_price, '50000.00')
+
+if __name__ == '__main__':
+    unittest.main()
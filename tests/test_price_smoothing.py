@@ -0,0 +1,48 @@
+# Run from the repo root: python3 -m unittest discover tests
+import unittest
+
+from stablechannels import ewma, needs_correction, stable_dollar_amount
+
+EXPECTED_DOLLAR_AMOUNT = 100
+ALPHA = 0.3
+
+def expected_msats_at(price):
+    return EXPECTED_DOLLAR_AMOUNT * 10**11 / price
+
+# $100 of BTC at $50,000; the receiver's balance stays put while we only check
+BALANCE = expected_msats_at(50000)
+
+# Runs the scenario 1 check for each price tick, the way check_stables does,
+# and returns whether each tick would have triggered a correction
+def corrections(prices, smoothing):
+    smoothed = None
+    triggered = []
+    for price in prices:
+        target = expected_msats_at(price)
+        if smoothing == 'ewma':
+            smoothed = ewma(smoothed, target, ALPHA)
+            target = round(smoothed)
+        dollars = stable_dollar_amount(BALANCE, EXPECTED_DOLLAR_AMOUNT, target)
+        triggered.append(needs_correction(EXPECTED_DOLLAR_AMOUNT, dollars))
+    return triggered
+
+# A 0.02% move is worth $0.02 on a $100 peg, above the $0.01 threshold
+SPIKE = 50010
+
+class TestPriceSmoothing(unittest.TestCase):
+    def test_ewma_first_value_is_the_price(self):
+        self.assertEqual(ewma(None, 123, ALPHA), 123)
+
+    def test_one_tick_spike_corrects_under_raw(self):
+        self.assertEqual(corrections([50000, SPIKE, 50000], 'raw'), [False, True, False])
+
+    def test_one_tick_spike_is_absorbed_under_ewma(self):
+        self.assertEqual(corrections([50000, SPIKE, 50000, 50000], 'ewma'), [False, False, False, False])
+
+    def test_sustained_move_corrects_under_ewma(self):
+        triggered = corrections([50000] + [SPIKE] * 5, 'ewma')
+        self.assertFalse(triggered[1])
+        self.assertTrue(triggered[-1])
+
+if __name__ == '__main__':
+    unittest.main()
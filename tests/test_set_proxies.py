@@ -0,0 +1,43 @@
+# Run from the repo root: python3 -m unittest discover tests
+import unittest
+from types import SimpleNamespace
+
+from stablechannels import set_proxies
+
+def fake_plugin(listconfigs):
+    return SimpleNamespace(rpc=SimpleNamespace(listconfigs=lambda: listconfigs))
+
+TOR = {'https': 'socks5h://127.0.0.1:9050', 'http': 'socks5h://127.0.0.1:9050'}
+
+class TestSetProxies(unittest.TestCase):
+    def test_top_level_config(self):
+        # Before CLN 23.08
+        plugin = fake_plugin({'always-use-proxy': True, 'proxy': '127.0.0.1:9050'})
+        set_proxies(plugin)
+        self.assertEqual(plugin.proxies, TOR)
+
+    def test_configs_shape(self):
+        # CLN 23.08 and later
+        plugin = fake_plugin({'configs': {
+            'always-use-proxy': {'value_bool': True, 'source': 'cmdline'},
+            'proxy': {'value_str': '127.0.0.1', 'source': 'cmdline'},
+        }})
+        set_proxies(plugin)
+        self.assertEqual(plugin.proxies, TOR)
+
+    def test_proxy_not_forced(self):
+        for listconfigs in [{'proxy': '127.0.0.1:9050'},
+                            {'configs': {'always-use-proxy': {'value_bool': False, 'source': 'default'},
+                                         'proxy': {'value_str': '127.0.0.1:9050', 'source': 'cmdline'}}},
+                            {'configs': {}}]:
+            plugin = fake_plugin(listconfigs)
+            set_proxies(plugin)
+            self.assertIsNone(plugin.proxies, listconfigs)
+
+    def test_forced_without_proxy(self):
+        plugin = fake_plugin({'configs': {'always-use-proxy': {'value_bool': True, 'source': 'cmdline'}}})
+        with self.assertRaises(Exception):
+            set_proxies(plugin)
+
+if __name__ == '__main__':
+    unittest.main()